@@ -1,73 +1,329 @@
 #![allow(dead_code)]
 
-use std::{io, process::Command};
-use trebuchet::utils::{EmailType, file_exists, User};
-use trebuchet::database;
-use clap::{Arg, App};
+use std::{env, fs, io, io::Write, process, thread};
+use std::path::Path;
+use trebuchet::database::ContentType;
+use trebuchet::error::{build_input_error, TrebuchetError};
+use trebuchet::utils::{self, EmailType, Role, User};
+use trebuchet::{certificates, config, database, gemini, reports, web};
+use clap::{AppSettings, Arg, App, ArgMatches, SubCommand};
+use chrono::NaiveDate;
 
 // ************************************************************
 //  DATABASE
 // ************************************************************
 
 // **********
-//  BUILD
-// **********
-
-fn build() {
-  // check that sqlite is installed
-  println!("Checking for sqlite...");
-  let sqlite_check = Command::new("which")
-  .arg("sqlite3")
-  .status()
-  .expect("'which' command failed when checking for sqlite3");
-
-  if sqlite_check.success() {
-    println!("✔   sqlite installed");
-    // create new DB file
-    let installed = file_exists("./trebuchet.db");
-    match installed {
-      Ok(()) => eprintln!("⚠️  database already exists"),
-      Err(err) => {
-        if err.kind() == std::io::ErrorKind::NotFound {
-            match database::build_tables() {
-              Ok(()) => {
-                println!("✔   database created");
-                // create web and capsule directories
-                match database::create_default_files() {
-                  Ok(()) => { 
-                    println!("😎  You are ready to use Trebuchet")
-                },
-                  Err(err) => eprintln!("Error creating default files: {}", err)
-                }
-              },
-              Err(err) => eprintln!("Error creating database: {}", err)
-            }
-        } else {
-          eprintln!("⚠️  Cannot read DB: {}", err)
-        }
-      }
+//  INIT
+// **********
+
+fn ask(question: &str, default: &str) -> String {
+  print!("{} [{}]: ", question, default);
+  io::stdout().flush().expect("failed to write to stdout");
+  let mut answer = String::new();
+  io::stdin()
+      .read_line(&mut answer)
+      .expect("failed to read from stdin");
+  match answer.trim() {
+    "" => default.to_string(),
+    a => a.to_string()
+  }
+}
+
+// running the installer again with the same answers is safe, so it can be used from Ansible
+fn init_command(matches: &ArgMatches) -> Result<(), TrebuchetError> {
+  let interactive = !matches.is_present("yes");
+  let value = |name: &str, question: &str, default: &str| match matches.value_of(name) {
+    Some(v) => v.to_string(),
+    None if interactive => ask(question, default),
+    None => default.to_string()
+  };
+  let root = value("root", "Directory for the database, web files and capsules", "/srv/trebuchet/");
+  let config_file = value("config", "Config file", config::DEFAULT_CONFIG_FILE);
+  let email = value("admin-email", "Email address of the first user", "");
+  let capsule = value("admin-capsule", "Their capsule: a domain or ~name, blank to serve it from the root", "");
+  if email.is_empty() {
+    return Err(build_input_error("An email address for the first user is required (--admin-email)".to_string()))
+  }
+  utils::validate_capsule(&capsule)?;
+
+  let root = root.trim_end_matches('/');
+  let mut settings = vec![
+    ("TREBUCHET_DB", format!("{}/trebuchet.db", root)),
+    ("TREBUCHET_CAPSULES", format!("{}/capsules", root)),
+    ("TREBUCHET_WEB", format!("{}/web", root))
+  ];
+  if let Some(url) = matches.value_of("url") {
+    settings.push(("TREBUCHET_URL", url.to_string()));
+  }
+
+  if interactive {
+    println!("\nTrebuchet will be installed with:");
+    println!("    config file: {}", config_file);
+    for (key, value) in &settings {
+      println!("    {}: {}", key, value);
+    }
+    println!("    first user: {} with capsule '{}'", email, capsule);
+    if ask("Continue?", "y").to_lowercase() != "y" {
+      return Err(build_input_error("Installation cancelled".to_string()))
+    }
+  }
+
+  fs::create_dir_all(root)?;
+  config::write_file(&config_file, &settings)?;
+  println!("✔   config written to {}", config_file);
+  // use the new locations for the rest of the installation
+  for (key, value) in &settings {
+    env::set_var(key, value);
+  }
+
+  database::build_tables()?;
+  println!("✔   database ready at {}", config::database());
+  database::create_default_files()?;
+
+  match database::get_user(&email) {
+    Ok(_) => println!("⚠️  {} already exists", email),
+    Err(_) => {
+      database::confirm_user(database::add_user(User::new(email.clone(), capsule))?)?.initiate_capsule()?;
+      User::new(email.clone(), String::new()).set_admin(true)?;
+      println!("✔   {} added and confirmed as an admin", email);
     }
-  } else {
-    println!("⚠️  sqlite must be installed to run Trebuchet. See https://www.sqlite.org for options")
   }
+
+  if config_file != config::DEFAULT_CONFIG_FILE {
+    println!("ℹ️  Set TREBUCHET_CONFIG={} when running trebuchet", config_file);
+  }
+  println!("😎  You are ready to use Trebuchet");
+  Ok(())
 }
 
 
 // **********
-//  ADD USER
+//  USERS
 // **********
 
-// **********
-//  REMOVE USER
-// **********
+fn user_command(matches: &ArgMatches) -> Result<(), TrebuchetError> {
+  match matches.subcommand() {
+    ("add", Some(args)) => {
+      let email = args.value_of("EMAIL").unwrap();
+      User::new(email.to_string(), args.value_of("CAPSULE").unwrap().to_string()).add()?;
+      println!("✔  User {} added to database", email);
+      Ok(())
+    },
+    ("rm", Some(args)) => {
+      let email = args.value_of("EMAIL").unwrap();
+      if !args.is_present("yes") {
+        println!("You are about to delete the following user: {}", email);
+        println!("Confirm this is what you want to do by typing the user email again below:");
+        let mut input_text = String::new();
+        io::stdin().read_line(&mut input_text)?;
+        if input_text.trim() != email {
+          return Err(build_input_error("Your text does not match the user email. Deletion aborted.".to_string()))
+        }
+      }
+      let report = User::new(email.to_string(), args.value_of("CAPSULE").unwrap().to_string()).delete(args.value_of("export"))?;
+      println!("✔  {}", report);
+      Ok(())
+    },
+    ("show", Some(args)) => {
+      let report = reports::user(args.value_of("USER").unwrap())?;
+      match args.is_present("json") {
+        true => println!("{}", report.to_json()),
+        false => println!("{}", report)
+      }
+      Ok(())
+    },
+    ("list", Some(args)) => {
+      let filter = reports::UserFilter {
+        confirmed: match (args.is_present("confirmed"), args.is_present("unconfirmed")) {
+          (true, _) => Some(true),
+          (_, true) => Some(false),
+          _ => None
+        },
+        published_before: date_arg(args, "published-before")?,
+        published_after: date_arg(args, "published-after")?,
+        sort: match args.value_of("sort") {
+          Some("posts") => reports::UserSort::Posts,
+          Some("storage") => reports::UserSort::Storage,
+          _ => reports::UserSort::Email
+        }
+      };
+      let users = reports::users(&filter)?;
+      match args.is_present("json") {
+        true => println!("{}", serde_json::Value::Array(users.iter().map(|u| u.to_json()).collect())),
+        false => {
+          println!("{}", reports::users_heading());
+          for user in users {
+            println!("{}", user)
+          }
+        }
+      }
+      Ok(())
+    },
+    ("prune", Some(args)) => {
+      let dry_run = args.is_present("dry-run");
+      let pruned = utils::prune_invitations(dry_run)?;
+      if pruned.is_empty() {
+        println!("✔  No invitations have expired")
+      }
+      for user in pruned {
+        match dry_run {
+          true => println!("Would remove {} and free the capsule '{}'", user.email, user.capsule),
+          false => println!("✔  Removed {} and freed the capsule '{}'", user.email, user.capsule)
+        }
+      }
+      Ok(())
+    },
+    ("confirm", Some(args)) => {
+      User::new(args.value_of("EMAIL").unwrap().to_string(), String::new()).initiate_login(EmailType::Confirm, None)
+    },
+    ("login", Some(args)) => {
+      User::new(args.value_of("EMAIL").unwrap().to_string(), String::new()).initiate_login(EmailType::LogIn, None)
+    },
+    ("api-key", Some(args)) => {
+      let email = args.value_of("EMAIL").unwrap();
+      let key = User::new(email.to_string(), String::new()).create_api_key()?;
+      println!("✔  API key created for {}: {}", email, key);
+      println!("   Use your email address as the username and this key as the password in your blog editor. It will not be shown again.");
+      Ok(())
+    },
+    ("rename", Some(args)) => {
+      let (email, new_capsule) = (args.value_of("EMAIL").unwrap(), args.value_of("NEW_CAPSULE").unwrap());
+      let old_capsule = database::get_user(email)?.capsule;
+      let (_, files) = User::new(email.to_string(), String::new()).rename_capsule(new_capsule, args.is_present("redirect"))?;
+      println!("✔  Capsule '{}' moved to '{}' with {} published files", old_capsule, new_capsule, files);
+      if args.is_present("redirect") {
+        println!("   Gemini requests for '{}' will redirect to '{}'", old_capsule, new_capsule);
+      }
+      Ok(())
+    },
+    ("quota", Some(args)) => {
+      let email = args.value_of("EMAIL").unwrap();
+      // check every limit before setting any of them
+      let mut limits = Vec::new();
+      for (name, quota, bytes) in [("documents", "max_documents", false), ("content", "max_content_bytes", true), ("published", "max_published_bytes", true)] {
+        if let Some(value) = args.value_of(name) {
+          limits.push((quota, limit_arg(name, value, bytes)?));
+        }
+      }
+      for (quota, limit) in limits {
+        database::set_quota(email, quota, limit)?;
+      }
+      let report = reports::quota(email)?;
+      match args.is_present("json") {
+        true => println!("{}", report.to_json()),
+        false => println!("{}", report)
+      }
+      Ok(())
+    },
+    ("admin", Some(args)) => {
+      let email = args.value_of("EMAIL").unwrap();
+      let admin = !args.is_present("revoke");
+      User::new(email.to_string(), String::new()).set_admin(admin)?;
+      match admin {
+        true => println!("✔  {} is now an admin", email),
+        false => println!("✔  {} is no longer an admin", email)
+      }
+      Ok(())
+    },
+    ("role", Some(args)) => {
+      let email = args.value_of("EMAIL").unwrap();
+      let capsule = args.value_of("CAPSULE").unwrap();
+      let role = match args.value_of("ROLE").unwrap() {
+        "none" => None,
+        role => Some(role.parse()?)
+      };
+      User::new(email.to_string(), String::new()).set_role(capsule, role)?;
+      match role {
+        Some(role) => println!("✔  {} is now an {} of '{}'", email, role, capsule),
+        None => println!("✔  {} no longer has a role in '{}'", email, capsule)
+      }
+      Ok(())
+    },
+    ("email", Some(args)) => {
+      let (email, new_email) = (args.value_of("EMAIL").unwrap(), args.value_of("NEW_EMAIL").unwrap());
+      User::new(email.to_string(), String::new()).request_email_change(new_email)?;
+      println!("✔  Confirmation sent to {}: the address changes when its link is opened", new_email);
+      Ok(())
+    },
+    ("name", Some(args)) => {
+      let email = args.value_of("EMAIL").unwrap();
+      User::new(email.to_string(), String::new()).set_name(args.value_of("NAME"))?;
+      println!("✔  Bylines for {} now read 'By {}'", email, database::get_author_name(email)?);
+      Ok(())
+    },
+    ("lang", Some(args)) => {
+      let email = args.value_of("EMAIL").unwrap();
+      User::new(email.to_string(), String::new()).set_lang(args.value_of("LANG").unwrap())?;
+      println!("✔  Language set for {}", email);
+      Ok(())
+    },
+    ("cert", Some(args)) => certificate_command(args),
+    _ => Err(build_input_error("Unknown user command: see `trebuchet user --help`".to_string()))
+  }
+}
 
-// **********
-//  UPSERT FILE
-// **********
+// dates for filters are given as YYYY-MM-DD
+fn date_arg(matches: &ArgMatches, name: &str) -> Result<Option<String>, TrebuchetError> {
+  match matches.value_of(name) {
+    Some(date) => match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+      Ok(date) => Ok(Some(date.format("%Y-%m-%d").to_string())),
+      Err(_) => Err(build_input_error(format!("--{} must be a date like 2021-06-30", name)))
+    },
+    None => Ok(None)
+  }
+}
 
-// **********
-//  DELETE FILE
-// **********
+// a number, optionally with K, M or G for sizes, or "unlimited", or "default" to follow the config file
+fn limit_arg(name: &str, value: &str, bytes: bool) -> Result<database::Limit, TrebuchetError> {
+  let invalid = || build_input_error(format!("--{} must be a number, unlimited or default", name));
+  match value {
+    "default" => Ok(database::Limit::Default),
+    "unlimited" => Ok(database::Limit::Unlimited),
+    _ => {
+      let (number, multiplier) = match value.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) if bytes => (&value[..i], 1024),
+        Some((i, 'M')) | Some((i, 'm')) if bytes => (&value[..i], 1024 * 1024),
+        Some((i, 'G')) | Some((i, 'g')) if bytes => (&value[..i], 1024 * 1024 * 1024),
+        _ => (value, 1)
+      };
+      match number.parse::<u64>().ok().and_then(|n| n.checked_mul(multiplier)) {
+        Some(max) => Ok(database::Limit::Max(max)),
+        None => Err(invalid())
+      }
+    }
+  }
+}
+
+// TLS client certificates that can act for a user over Gemini and Titan
+fn certificate_command(matches: &ArgMatches) -> Result<(), TrebuchetError> {
+  match matches.subcommand() {
+    ("add", Some(args)) => {
+      let email = args.value_of("EMAIL").unwrap();
+      User::new(email.to_string(), String::new()).link_certificate(args.value_of("FINGERPRINT").unwrap())?;
+      println!("✔  Certificate linked to {}", email);
+      Ok(())
+    },
+    ("rm", Some(args)) => {
+      let email = args.value_of("EMAIL").unwrap();
+      User::new(email.to_string(), String::new()).unlink_certificate(args.value_of("FINGERPRINT").unwrap())?;
+      println!("✔  Certificate unlinked from {}", email);
+      Ok(())
+    },
+    ("list", Some(args)) => {
+      let email = args.value_of("EMAIL").unwrap();
+      let certificates = User::new(email.to_string(), String::new()).certificates()?;
+      if certificates.is_empty() {
+        println!("No certificates are linked to {}", email)
+      }
+      for (fingerprint, added) in certificates {
+        println!("{}  linked {}", fingerprint, added)
+      }
+      Ok(())
+    },
+    _ => Err(build_input_error("Unknown cert command: see `trebuchet user cert --help`".to_string()))
+  }
+}
 
 // ************************************************************
 //  WEBSITE
@@ -226,119 +482,602 @@ fn build() {
 //    - total storage?
 //    - version of trebuchet?
 
-fn main() {
-  let matches = App::new("Trebuchet")
-      .version("0.1.0")
-      .author("Hugh Rundle <hugh@hughrundle.net>")
-      .about("Publish and manage gemini sites from the web")
-      .arg(Arg::with_name("build")
-          .short("b")
-          .long("build")
-          .help("Set up a default user and web components")
-          .takes_value(false)
-          .conflicts_with_all(&["capsule", "delete", "listen", "user", "statistics"]))
-      .arg(Arg::with_name("capsule")
-          .short("c")
-          .long("capsule")
-          .help("Add a user with EMAIL address, whose gemini site will be saved to SUBDIRECTORY")
-          .value_names(&["EMAIL", "SUBDIRECTORY"])
-          .takes_value(true)
-          .conflicts_with_all(&["build", "delete", "listen", "user", "statistics"]))
-      .arg(Arg::with_name("delete")
-          .short("d")
-          .long("delete")
-          .help("Remove a user and their files by providing their EMAIL address and the SUBDIRECTORY their files are saved to")
-          .value_names(&["EMAIL", "SUBDIRECTORY"])
-          .takes_value(true)
-          .conflicts_with_all(&["build", "capsule", "user", "statistics"]))
-      .arg(Arg::with_name("listen")
-          .short("L")
-          .long("listen")
-          .help("Listen for web traffic")
-          .takes_value(false)
-          .conflicts_with_all(&["build", "capsule", "delete", "listen", "user", "statistics"]))
-      .arg(Arg::with_name("user")
-          .short("u")
-          .long("user")
-          .help("Display details for a particular user with EMAIL address")
-          .value_name("EMAIL")
-          .takes_value(true)
-          .conflicts_with_all(&["build", "delete", "listen", "capsule", "statistics"]))
-          .arg(Arg::with_name("login")
-          .short("l")
-          .long("login")
-          .help("Send login email")
-          .takes_value(false)
-          .requires("user")
-          .conflicts_with_all(&["confirm", "build", "delete", "listen", "capsule", "statistics"]))
-          .arg(Arg::with_name("confirm")
-          .short("n")
-          .long("confirm")
-          .help("Send confirmation email")
-          .takes_value(false)
-          .requires("user")
-          .conflicts_with_all(&["login", "build", "delete", "listen", "capsule", "statistics"]))
-      .arg(Arg::with_name("statistics")
-          .short("s")
-          .long("statistics")
-          .help("Display statistics about this trebuchet installation")
-          .takes_value(false)
-          .conflicts_with_all(&["build", "capsule", "user", "capsule"]))
-      .get_matches();
+// **********
+//  DOCUMENTS
+// **********
 
-  // it's ok to use unwrap here because clap ensures there will be the required a present
-  if matches.is_present("build") {
-    build()
+fn doc_command(matches: &ArgMatches) -> Result<(), TrebuchetError> {
+  match matches.subcommand() {
+    ("list", Some(args)) => {
+      let user = database::find_user(args.value_of("USER").unwrap())?;
+      for doc in database::get_documents(&user.capsule)? {
+        println!("{:>5}  {:<8} {}  {}  [{}]  {}", doc.id, doc.content_type.to_string(), doc.published, doc.title, doc.tags.join(", "), doc.author);
+      }
+      Ok(())
+    },
+    ("show", Some(args)) => {
+      let doc = database::get_document(document_id(args)?)?;
+      println!("Title:     {}", doc.title);
+      println!("Capsule:   {}", doc.capsule);
+      println!("Author:    {}", doc.author);
+      println!("Type:      {}", doc.content_type);
+      println!("Tags:      {}", doc.tags.join(", "));
+      println!("Published: {}", doc.published);
+      println!("Updated:   {}", doc.updated);
+      println!("URL:       /{}/", doc.url());
+      println!("\n{}", doc.content);
+      Ok(())
+    },
+    ("add", Some(args)) => {
+      let user = database::find_user(args.value_of("USER").unwrap())?;
+      let file = args.value_of("FILE").unwrap();
+      let content = fs::read_to_string(file)?;
+      let title = match args.value_of("title") {
+        Some(title) => title.to_string(),
+        None => heading(&content).unwrap_or_else(|| Path::new(file).file_stem().unwrap_or_default().to_string_lossy().to_string())
+      };
+      let content_type = args.value_of("type").unwrap_or("page").parse::<ContentType>()?;
+      // the user writes in their own capsule unless another is given
+      let capsule = args.value_of("capsule").unwrap_or(&user.capsule).to_string();
+      user.check_role(&capsule, Role::Author)?;
+      let doc = database::create_document(&capsule, &user.email, title, tag_list(args.value_of("tags")), content, content_type);
+      let id = database::save_content(doc)?;
+      println!("✔  Document {} added. Run `trebuchet publish {}` to publish it", id, capsule);
+      Ok(())
+    },
+    ("edit", Some(args)) => {
+      let old = database::get_document(document_id(args)?)?;
+      let mut doc = old.clone();
+      let content = edit(&doc.content)?;
+      let changed = content != doc.content || args.is_present("title") || args.is_present("tags") || args.is_present("type");
+      if !changed {
+        println!("No changes made");
+        return Ok(())
+      }
+      doc.content = content;
+      if let Some(title) = args.value_of("title") {
+        doc.title = title.to_string();
+      }
+      if args.is_present("tags") {
+        doc.tags = tag_list(args.value_of("tags"));
+      }
+      if let Some(content_type) = args.value_of("type") {
+        doc.content_type = content_type.parse::<ContentType>()?;
+      }
+      // save first, so a change that is refused leaves the published copy where it was
+      let doc = database::update_document(doc)?;
+      // publishing does not remove files, so clear the old location if it moves or is no longer published
+      let published = doc.content_type == ContentType::Page || doc.content_type == ContentType::Post;
+      if doc.url() != old.url() || !published {
+        database::unpublish_document(&old.capsule, &old)?;
+        // the archive, tag, author and index pages still link to the old location until they are written again
+        database::publish_capsule(&doc.capsule)?;
+        println!("✔  Document {} updated and {} published again", doc.id, doc.capsule);
+        return Ok(())
+      }
+      println!("✔  Document {} updated. Run `trebuchet publish {}` to publish it", doc.id, doc.capsule);
+      Ok(())
+    },
+    ("rm", Some(args)) => {
+      let doc = database::get_document(document_id(args)?)?;
+      database::unpublish_document(&doc.capsule, &doc)?;
+      database::delete_document(doc.id)?;
+      // so the archive, tag, author and index pages stop linking to it
+      database::publish_capsule(&doc.capsule)?;
+      println!("✔  Document {} removed from {} and the capsule published again", doc.id, doc.capsule);
+      Ok(())
+    },
+    _ => Err(build_input_error("Unknown doc command: see `trebuchet doc --help`".to_string()))
   }
-  if matches.is_present("listen") {
-    // TODO: ideally this runs in the background automatically thought that could perhaps better be a systemd service
-    println!("I am listening for web traffic...")
+}
+
+fn document_id(args: &ArgMatches) -> Result<i64, TrebuchetError> {
+  let id = args.value_of("ID").unwrap();
+  id.parse::<i64>().map_err(|_| build_input_error(format!("{} is not a document id", id)))
+}
+
+fn tag_list(tags: Option<&str>) -> Vec<String> {
+  tags.unwrap_or_default().split(',').map(str::trim).filter(|t| !t.is_empty()).map(str::to_string).collect()
+}
+
+// the text of the first level one heading
+fn heading(content: &str) -> Option<String> {
+  content.lines().find_map(|line| line.strip_prefix("# ")).map(|h| h.trim().to_string())
+}
+
+// open text in $EDITOR and return what was saved
+// the file gets a random name and is only readable by us, so nobody else can swap it for a link
+fn edit(text: &str) -> Result<String, TrebuchetError> {
+  let mut file = tempfile::Builder::new().prefix("trebuchet-").suffix(".gmi").tempfile()?;
+  file.write_all(text.as_bytes())?;
+  file.flush()?;
+  let path = file.path().to_path_buf();
+  let editor = env::var("EDITOR").unwrap_or_else(|_| String::from("vi"));
+  // EDITOR may include arguments, e.g. "code --wait"
+  let mut words = editor.split_whitespace();
+  let status = process::Command::new(words.next().unwrap_or("vi"))
+    .args(words)
+    .arg(&path)
+    .status();
+  let edited = fs::read_to_string(&path);
+  file.close()?;
+  match status?.success() {
+    true => Ok(edited?),
+    false => Err(build_input_error(format!("{} exited with an error, so nothing was saved", editor)))
   }
-  if matches.is_present("capsule") {
-    let args: Vec<&str> = matches.values_of("capsule").unwrap().collect();
-    match User::new(args[0].to_string(), args[1].to_string()).add() {
-      Ok(()) => println!("✔  User {} added to database", args[0]),
-      Err(err) => eprintln!("ERROR Could not build capsule: {}", err)
+}
+
+// **********
+//  PUBLISH
+// **********
+
+fn publish_command(matches: &ArgMatches) -> Result<(), TrebuchetError> {
+  let capsules = match matches.value_of("CAPSULE") {
+    Some(capsule) => vec![database::find_user(capsule)?.capsule],
+    None => database::get_capsules()?
+  };
+  for capsule in capsules {
+    let user = database::get_user_by_capsule(&capsule)?;
+    // --all only publishes capsules whose owners have confirmed
+    if matches.is_present("all") && !database::is_confirmed(&user.email)? {
+      println!("⚠️  Skipped '{}': {} has not confirmed", capsule, user.email);
+      continue
     }
+    database::publish_capsule(&capsule)?;
+    println!("✔  Published '{}'", capsule);
   }
-  if matches.is_present("delete") {
-    let args: Vec<&str> = matches.values_of("delete").unwrap().collect();
-    // TODO: move this into a function in lib
-    println!("You are about to delete the following user: {}", args[0]);
-    println!("Confirm this is what you want to do by typing the user email again below:");
-    let mut input_text = String::new();
-    io::stdin()
-        .read_line(&mut input_text)
-        .expect("failed to read from stdin");
-
-    let trimmed = input_text.trim();
-    let is_match = trimmed.parse::<String>() == Ok(args[0].to_string());
-    match is_match {
-        true => {
-          match User::new(args[0].to_string(), args[1].to_string()).delete() {
-            Ok(()) => println!("✔  User {} deleted from database", args[0]),
-            Err(err) => eprintln!("ERROR Could not delete capsule: {}", err)
-          }
-        },
-        false => eprintln!("⚠️  Your text does not match the user email. Deletion aborted."),
-    };
+  Ok(())
+}
+
+// **********
+//  SERVE
+// **********
+
+fn serve_command(matches: &ArgMatches) -> Result<(), TrebuchetError> {
+  match matches.value_of("SERVER").unwrap_or("web") {
+    "gemini" => gemini::listen(),
+    "all" => {
+      let gemini = thread::spawn(gemini::listen);
+      web::listen()?;
+      gemini.join().unwrap_or_else(|_| Err(build_input_error("The Gemini server stopped unexpectedly".to_string())))
+    },
+    _ => web::listen()
+  }
+}
+
+// **********
+//  REDIRECTS
+// **********
+
+fn redirect_command(matches: &ArgMatches) -> Result<(), TrebuchetError> {
+  match matches.subcommand() {
+    ("add", Some(args)) => {
+      let (capsule, source, target) = (args.value_of("CAPSULE").unwrap(), args.value_of("SOURCE").unwrap(), args.value_of("TARGET").unwrap());
+      database::get_user_by_capsule(capsule)?;
+      database::add_redirect(capsule, &format!("/{}", source.trim_start_matches('/')), target)?;
+      println!("✔  Requests for {} in {} will redirect to {}", source, capsule, target);
+      Ok(())
+    },
+    ("rm", Some(args)) => {
+      database::delete_redirect(args.value_of("CAPSULE").unwrap(), &format!("/{}", args.value_of("SOURCE").unwrap().trim_start_matches('/')))?;
+      println!("✔  Redirect removed");
+      Ok(())
+    },
+    ("list", Some(args)) => {
+      for (source, target) in database::get_redirects(args.value_of("CAPSULE").unwrap())? {
+        println!("{} => {}", source, target)
+      }
+      Ok(())
+    },
+    _ => Err(build_input_error("Unknown redirect command: see `trebuchet redirect --help`".to_string()))
   }
-  if matches.is_present("user") {
-    if matches.is_present("confirm") {
-      if let Err(err) = User::new(matches.value_of("user").unwrap().to_string(), "".to_string()).initiate_login(EmailType::Confirm) {
-        eprintln!("ERROR Could not resend confirmation email: {}", err)
+}
+
+// **********
+//  TLS CERTIFICATES
+// **********
+
+fn tls_command(matches: &ArgMatches) -> Result<(), TrebuchetError> {
+  match matches.subcommand() {
+    ("list", Some(_)) => {
+      let list = certificates::list()?;
+      if list.is_empty() {
+        println!("No certificates found in {}", config::certificates_dir())
       }
-    } else if matches.is_present("login") {
-      if let Err(err) = User::new(matches.value_of("user").unwrap().to_string(), "".to_string()).initiate_login(EmailType::LogIn) {
-        eprintln!("ERROR Could not send login email: {}", err)
+      for info in list {
+        println!("{:<30} {}  expires {} ({} days)", gemini::display_domain(&info.domain), info.fingerprint, info.expires.format("%Y-%m-%d"), info.days_remaining())
+      }
+      Ok(())
+    },
+    ("renew", Some(args)) => {
+      let days = match args.value_of("days") {
+        Some(days) => days.parse::<i64>().map_err(|_| build_input_error("--days must be a whole number".to_string()))?,
+        None => certificates::RENEW_WITHIN_DAYS
+      };
+      let written = certificates::renew(days)?;
+      if written.is_empty() {
+        println!("✔  No certificates needed renewing")
+      }
+      for info in written {
+        println!("✔  Certificate for {} written, expires {}: {}", info.domain, info.expires.format("%Y-%m-%d"), info.fingerprint)
+      }
+      Ok(())
+    },
+    _ => Err(build_input_error("Unknown tls command: see `trebuchet tls --help`".to_string()))
+  }
+}
+
+// **********
+//  AUDIT
+// **********
+
+fn audit_command(matches: &ArgMatches) -> Result<(), TrebuchetError> {
+  match matches.subcommand() {
+    ("list", Some(args)) => {
+      let entries = reports::audit(&audit_filter(args)?)?;
+      match args.is_present("json") {
+        // one object per line, like `audit export`
+        true => for entry in entries {
+          println!("{}", entry.to_json())
+        },
+        false => {
+          println!("{}", reports::audit_heading());
+          for entry in entries {
+            println!("{}", entry)
+          }
+        }
       }
-    } else {
-      // TODO: 
-        println!("I am printing user details!");
+      Ok(())
+    },
+    ("export", Some(args)) => {
+      let path = args.value_of("FILE").unwrap();
+      let count = reports::export_audit(&audit_filter(args)?, path)?;
+      println!("✔  {} audit log entries written to {}", count, path);
+      Ok(())
+    },
+    _ => Err(build_input_error("Unknown audit command: see `trebuchet audit --help`".to_string()))
+  }
+}
+
+fn audit_filter(args: &ArgMatches) -> Result<reports::AuditFilter, TrebuchetError> {
+  Ok(reports::AuditFilter {
+    actor: args.value_of("actor").map(str::to_string),
+    action: args.value_of("action").map(str::to_string),
+    target: args.value_of("target").map(str::to_string),
+    before: date_arg(args, "before")?,
+    after: date_arg(args, "after")?,
+    failed: args.is_present("failed"),
+    limit: match args.value_of("limit") {
+      Some(limit) => Some(limit.parse().map_err(|_| build_input_error("--limit must be a number".to_string()))?),
+      None => None
     }
+  })
+}
+
+// the same filters for `audit list` and `audit export`
+fn audit_filter_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+  vec![
+    Arg::with_name("actor")
+        .long("actor")
+        .help("Only entries by ACTOR, e.g. cli:root or web:someone@example.com")
+        .value_name("ACTOR")
+        .takes_value(true),
+    Arg::with_name("action")
+        .long("action")
+        .help("Only ACTION, e.g. doc.rm, or every action on something, e.g. user")
+        .value_name("ACTION")
+        .takes_value(true),
+    Arg::with_name("target")
+        .long("target")
+        .help("Only entries about this email address or capsule")
+        .value_name("TARGET")
+        .takes_value(true),
+    Arg::with_name("after")
+        .long("after")
+        .help("Only entries after DATE")
+        .value_name("DATE")
+        .takes_value(true),
+    Arg::with_name("before")
+        .long("before")
+        .help("Only entries before DATE")
+        .value_name("DATE")
+        .takes_value(true),
+    Arg::with_name("failed").long("failed").help("Only actions that failed"),
+    Arg::with_name("limit")
+        .long("limit")
+        .help("Only the latest N entries")
+        .value_name("N")
+        .takes_value(true)
+  ]
+}
+
+// **********
+//  STATS
+// **********
+
+fn stats_command(matches: &ArgMatches) -> Result<(), TrebuchetError> {
+  let statistics = reports::statistics()?;
+  match matches.is_present("json") {
+    true => println!("{}", statistics.to_json()),
+    false => println!("{}", statistics)
+  }
+  Ok(())
+}
+
+fn main() {
+  let content_types = ["draft", "include", "page", "post"];
+  let matches = App::new("Trebuchet")
+      .version("0.1.0")
+      .author("Hugh Rundle <hugh@hughrundle.net>")
+      .about("Publish and manage gemini sites from the web")
+      .setting(AppSettings::SubcommandRequiredElseHelp)
+      .subcommand(SubCommand::with_name("init")
+          .about("Install Trebuchet: write the config file, create the database and directories, and add the first user")
+          .arg(Arg::with_name("yes")
+              .short("y")
+              .long("yes")
+              .help("Install without asking questions, using the flags below or their defaults"))
+          .arg(Arg::with_name("root")
+              .long("root")
+              .help("Directory for the database, web files and capsules (default /srv/trebuchet/)")
+              .value_name("DIRECTORY")
+              .takes_value(true))
+          .arg(Arg::with_name("config")
+              .long("config")
+              .help("Where to write the config file (default /etc/trebuchet.conf)")
+              .value_name("FILE")
+              .takes_value(true))
+          .arg(Arg::with_name("admin-email")
+              .long("admin-email")
+              .help("Email address of the first user, who is confirmed straight away")
+              .value_name("EMAIL")
+              .takes_value(true))
+          .arg(Arg::with_name("admin-capsule")
+              .long("admin-capsule")
+              .help("The first user's capsule: a domain or ~name (default none: served from the root)")
+              .value_name("CAPSULE")
+              .takes_value(true))
+          .arg(Arg::with_name("url")
+              .long("url")
+              .help("Public URL of the web interface, used for links in emails")
+              .value_name("URL")
+              .takes_value(true)))
+      .subcommand(SubCommand::with_name("user")
+          .about("Manage users")
+          .setting(AppSettings::SubcommandRequiredElseHelp)
+          .subcommand(SubCommand::with_name("add")
+              .about("Add a user whose gemini site will be saved to CAPSULE, and send them a confirmation email")
+              .arg(Arg::with_name("EMAIL").required(true))
+              .arg(Arg::with_name("CAPSULE").help("A domain like gemini.example.com, or ~name").required(true)))
+          .subcommand(SubCommand::with_name("rm")
+              .about("Remove a user, their documents and their published files")
+              .arg(Arg::with_name("EMAIL").required(true))
+              .arg(Arg::with_name("CAPSULE").required(true))
+              .arg(Arg::with_name("export")
+                  .long("export")
+                  .help("Save a tar archive of the user's documents and published capsule to FILE before deleting them")
+                  .value_name("FILE")
+                  .takes_value(true))
+              .arg(Arg::with_name("yes")
+                  .short("y")
+                  .long("yes")
+                  .help("Do not ask for confirmation")))
+          .subcommand(SubCommand::with_name("show")
+              .about("Display details for a user")
+              .arg(Arg::with_name("USER").help("Email address or capsule").required(true))
+              .arg(Arg::with_name("json").long("json").help("Print as JSON")))
+          .subcommand(SubCommand::with_name("list")
+              .about("List users, to find abandoned invitations and dormant capsules")
+              .arg(Arg::with_name("confirmed").long("confirmed").help("Only list confirmed users").conflicts_with("unconfirmed"))
+              .arg(Arg::with_name("unconfirmed").long("unconfirmed").help("Only list users who have not confirmed their account"))
+              .arg(Arg::with_name("published-before")
+                  .long("published-before")
+                  .help("Only list users who last published before DATE, or never")
+                  .value_name("DATE")
+                  .takes_value(true))
+              .arg(Arg::with_name("published-after")
+                  .long("published-after")
+                  .help("Only list users who last published after DATE")
+                  .value_name("DATE")
+                  .takes_value(true))
+              .arg(Arg::with_name("sort")
+                  .long("sort")
+                  .help("Sort by email (default), or largest first by posts or storage")
+                  .possible_values(&["email", "posts", "storage"])
+                  .takes_value(true))
+              .arg(Arg::with_name("json").long("json").help("Print as JSON")))
+          .subcommand(SubCommand::with_name("prune")
+              .about("Remove users who did not confirm their account before the invitation expired")
+              .arg(Arg::with_name("dry-run").long("dry-run").help("List the users who would be removed without removing them")))
+          .subcommand(SubCommand::with_name("confirm")
+              .about("Send the confirmation email again, while the invitation has not expired")
+              .arg(Arg::with_name("EMAIL").required(true)))
+          .subcommand(SubCommand::with_name("login")
+              .about("Send a login email")
+              .arg(Arg::with_name("EMAIL").required(true)))
+          .subcommand(SubCommand::with_name("api-key")
+              .about("Create an API key for desktop blog editors (MetaWeblog/XML-RPC)")
+              .arg(Arg::with_name("EMAIL").required(true)))
+          .subcommand(SubCommand::with_name("rename")
+              .about("Move a user's capsule to NEW_CAPSULE, taking its published files and republishing")
+              .arg(Arg::with_name("EMAIL").required(true))
+              .arg(Arg::with_name("NEW_CAPSULE").help("A domain like gemini.example.com, or ~name").required(true))
+              .arg(Arg::with_name("redirect")
+                  .long("redirect")
+                  .help("Redirect Gemini requests for the old capsule to the new one")))
+          .subcommand(SubCommand::with_name("quota")
+              .about("Show a user's quotas and usage, or set them for this user only")
+              .arg(Arg::with_name("EMAIL").required(true))
+              .arg(Arg::with_name("documents")
+                  .long("documents")
+                  .help("Most documents the user can have: a number, unlimited, or default to follow the config file")
+                  .value_name("LIMIT")
+                  .takes_value(true))
+              .arg(Arg::with_name("content")
+                  .long("content")
+                  .help("Most bytes of document source, e.g. 5M, unlimited, or default")
+                  .value_name("LIMIT")
+                  .takes_value(true))
+              .arg(Arg::with_name("published")
+                  .long("published")
+                  .help("Most bytes of published files, e.g. 50M, unlimited, or default")
+                  .value_name("LIMIT")
+                  .takes_value(true))
+              .arg(Arg::with_name("json").long("json").help("Print as JSON")))
+          .subcommand(SubCommand::with_name("admin")
+              .about("Let a user manage users from the web dashboard")
+              .arg(Arg::with_name("EMAIL").required(true))
+              .arg(Arg::with_name("revoke").long("revoke").help("Stop the user being an admin")))
+          .subcommand(SubCommand::with_name("role")
+              .about("Give a user a role in someone else's capsule: authors write drafts, editors publish anyone's drafts")
+              .arg(Arg::with_name("EMAIL").required(true))
+              .arg(Arg::with_name("CAPSULE").required(true))
+              .arg(Arg::with_name("ROLE").required(true).possible_values(&["editor", "author", "none"])))
+          .subcommand(SubCommand::with_name("email")
+              .about("Change a user's email address, once they open the link sent to NEW_EMAIL")
+              .arg(Arg::with_name("EMAIL").required(true))
+              .arg(Arg::with_name("NEW_EMAIL").required(true)))
+          .subcommand(SubCommand::with_name("name")
+              .about("Set the NAME shown in the user's bylines, or go back to the start of their email address without one")
+              .arg(Arg::with_name("EMAIL").required(true))
+              .arg(Arg::with_name("NAME")))
+          .subcommand(SubCommand::with_name("lang")
+              .about("Set the LANG declared when the built-in Gemini server serves the user's capsule, e.g. en")
+              .arg(Arg::with_name("EMAIL").required(true))
+              .arg(Arg::with_name("LANG").required(true)))
+          .subcommand(SubCommand::with_name("cert")
+              .about("Manage the TLS client certificates used for Titan uploads and the Gemini admin area")
+              .setting(AppSettings::SubcommandRequiredElseHelp)
+              .subcommand(SubCommand::with_name("add")
+                  .about("Link the certificate with SHA-256 FINGERPRINT to the user")
+                  .arg(Arg::with_name("EMAIL").required(true))
+                  .arg(Arg::with_name("FINGERPRINT").required(true)))
+              .subcommand(SubCommand::with_name("rm")
+                  .about("Unlink the certificate with SHA-256 FINGERPRINT from the user")
+                  .arg(Arg::with_name("EMAIL").required(true))
+                  .arg(Arg::with_name("FINGERPRINT").required(true)))
+              .subcommand(SubCommand::with_name("list")
+                  .about("List the certificates linked to the user")
+                  .arg(Arg::with_name("EMAIL").required(true)))))
+      .subcommand(SubCommand::with_name("doc")
+          .about("Manage documents")
+          .setting(AppSettings::SubcommandRequiredElseHelp)
+          .subcommand(SubCommand::with_name("list")
+              .about("List a user's documents")
+              .arg(Arg::with_name("USER").help("Email address or capsule").required(true)))
+          .subcommand(SubCommand::with_name("show")
+              .about("Show a document and its details")
+              .arg(Arg::with_name("ID").required(true)))
+          .subcommand(SubCommand::with_name("add")
+              .about("Add the gemtext in FILE as a new document for USER")
+              .arg(Arg::with_name("USER").help("Email address or capsule").required(true))
+              .arg(Arg::with_name("FILE").required(true))
+              .arg(Arg::with_name("title").long("title").takes_value(true).help("Defaults to the first heading, or the file name"))
+              .arg(Arg::with_name("type").long("type").takes_value(true).possible_values(&content_types).help("Defaults to page"))
+              .arg(Arg::with_name("tags").long("tags").takes_value(true).help("Comma separated, e.g. a,b"))
+              .arg(Arg::with_name("capsule").long("capsule").takes_value(true).help("A capsule USER has a role in: defaults to their own")))
+          .subcommand(SubCommand::with_name("edit")
+              .about("Edit a document in $EDITOR")
+              .arg(Arg::with_name("ID").required(true))
+              .arg(Arg::with_name("title").long("title").takes_value(true))
+              .arg(Arg::with_name("type").long("type").takes_value(true).possible_values(&content_types))
+              .arg(Arg::with_name("tags").long("tags").takes_value(true).help("Comma separated, replacing the existing tags")))
+          .subcommand(SubCommand::with_name("rm")
+              .about("Remove a document and its published files")
+              .arg(Arg::with_name("ID").required(true))))
+      .subcommand(SubCommand::with_name("publish")
+          .about("Write the gemini files for a capsule")
+          .arg(Arg::with_name("CAPSULE").help("Capsule or owner's email address").required_unless("all"))
+          .arg(Arg::with_name("all").long("all").help("Publish every confirmed capsule").conflicts_with("CAPSULE")))
+      .subcommand(SubCommand::with_name("serve")
+          .about("Listen for web traffic, or serve published capsules over Gemini and accept Titan uploads")
+          .arg(Arg::with_name("SERVER").possible_values(&["web", "gemini", "all"]).help("Defaults to web")))
+      .subcommand(SubCommand::with_name("redirect")
+          .about("Manage redirects served by the built-in Gemini server")
+          .setting(AppSettings::SubcommandRequiredElseHelp)
+          .subcommand(SubCommand::with_name("add")
+              .about("Redirect requests for SOURCE in CAPSULE to TARGET, a path in the capsule or a full URL")
+              .arg(Arg::with_name("CAPSULE").required(true))
+              .arg(Arg::with_name("SOURCE").required(true))
+              .arg(Arg::with_name("TARGET").required(true)))
+          .subcommand(SubCommand::with_name("rm")
+              .about("Remove the redirect for SOURCE in CAPSULE")
+              .arg(Arg::with_name("CAPSULE").required(true))
+              .arg(Arg::with_name("SOURCE").required(true)))
+          .subcommand(SubCommand::with_name("list")
+              .about("List the redirects for CAPSULE")
+              .arg(Arg::with_name("CAPSULE").required(true))))
+      .subcommand(SubCommand::with_name("tls")
+          .about("Manage the TLS certificates the Gemini server uses")
+          .setting(AppSettings::SubcommandRequiredElseHelp)
+          .subcommand(SubCommand::with_name("list")
+              .about("List certificates with their fingerprints and expiry dates"))
+          .subcommand(SubCommand::with_name("renew")
+              .about("Create certificates for domain capsules without one, and renew those close to expiry")
+              .arg(Arg::with_name("days")
+                  .long("days")
+                  .help("Renew certificates expiring within DAYS (default 30)")
+                  .value_name("DAYS")
+                  .takes_value(true))))
+      .subcommand(SubCommand::with_name("audit")
+          .about("Review the log of changes to users, documents and capsules")
+          .setting(AppSettings::SubcommandRequiredElseHelp)
+          .subcommand(SubCommand::with_name("list")
+              .about("List audit log entries, oldest first")
+              .args(&audit_filter_args())
+              .arg(Arg::with_name("json").long("json").help("Print as JSON, one entry per line")))
+          .subcommand(SubCommand::with_name("export")
+              .about("Write audit log entries to a file as JSON, one entry per line")
+              .arg(Arg::with_name("FILE").help("File to write").required(true))
+              .args(&audit_filter_args())))
+      .subcommand(SubCommand::with_name("stats")
+          .about("Display statistics about this trebuchet installation")
+          .arg(Arg::with_name("json").long("json").help("Print as JSON")))
+      .get_matches();
+
+  // changes made from the command line are recorded against the account running it
+  utils::set_actor(format!("cli:{}", env::var("USER").or_else(|_| env::var("LOGNAME")).unwrap_or_else(|_| "unknown".to_string())));
+  // clap ensures required arguments are present, so it's ok to use unwrap on them
+  let result = match matches.subcommand() {
+    ("init", Some(args)) => init_command(args),
+    ("user", Some(args)) => user_command(args),
+    ("doc", Some(args)) => doc_command(args),
+    ("publish", Some(args)) => publish_command(args),
+    ("serve", Some(args)) => serve_command(args),
+    ("redirect", Some(args)) => redirect_command(args),
+    ("tls", Some(args)) => tls_command(args),
+    ("audit", Some(args)) => audit_command(args),
+    ("stats", Some(args)) => stats_command(args),
+    _ => Ok(())
+  };
+  if let Err(err) = result {
+    eprintln!("ERROR {} ({})", err, err.message);
+    process::exit(err.exit_code())
   }
-  if matches.is_present("statistics") {
-    // TODO:
-      println!("I am printing statistics!")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn doc_tag_lists_are_trimmed() {
+    assert_eq!(tag_list(Some(" rust, gemini ,,blogging ")), vec!["rust", "gemini", "blogging"]);
+    assert!(tag_list(Some(" , ")).is_empty());
+    assert!(tag_list(None).is_empty());
+  }
+
+  #[test]
+  fn doc_titles_come_from_the_first_heading() {
+    assert_eq!(heading("intro\n## Not this\n# My Page \n# Later"), Some("My Page".to_string()));
+    assert_eq!(heading("#No space\ntext"), None);
+  }
+
+  #[test]
+  fn doc_ids_must_be_numbers() {
+    let app = || App::new("doc").arg(Arg::with_name("ID").required(true));
+    assert_eq!(document_id(&app().get_matches_from(vec!["doc", "42"])).unwrap(), 42);
+    let error = document_id(&app().get_matches_from(vec!["doc", "forty-two"])).err().unwrap();
+    assert_eq!(error.message, "forty-two is not a document id");
   }
 }